@@ -1,6 +1,15 @@
 -- Agent.nvim Lua Interface
 local M = {}
 
+-- Protocol version spoken by this frontend; the backend must share the major version
+local PROTOCOL_VERSION = '1.0.0'
+
+-- Agent windows in focus cycling order
+local FOCUS_ORDER = { 'chat', 'input', 'command' }
+
+-- Spec phase documents, in workflow order
+local PHASE_DOCUMENTS = { 'requirements.md', 'design.md', 'tasks.md' }
+
 -- Plugin state
 local state = {
   initialized = false,
@@ -8,6 +17,7 @@ local state = {
   windows = {},
   current_spec = nil,
   chat_history = {},
+  inactivity_timer = nil,
 }
 
 -- Configuration
@@ -16,20 +26,129 @@ local default_config = {
   rust_binary_path = nil, -- Will be auto-detected
   log_level = 'info',
   ui = {
+    layout = 'float', -- 'float', 'right' (vertical split) or 'bottom' (horizontal split)
+    anchor = 'center', -- Float position: 'center', 'top' or 'bottom'
     border_style = 'rounded',
     window_width_ratio = 0.8,
     window_height_ratio = 0.6,
+    split_width_ratio = 0.35, -- Width of the 'right' layout split
+    max_input_height = 10, -- Input window grows up to this many lines
+    inactivity_timeout = nil, -- Seconds before the interface auto-closes (nil disables)
+    selection_context_lines = 3, -- Lines around a selection sent by :SpecAskSelection
   },
   keybindings = {
     open_agent = '<leader>af',
     new_spec = '<leader>sn',
     open_spec = '<leader>so',
     close_agent = '<Esc>',
-  }
+  },
+  -- Prompt templates registered as :Spec<Name> commands
+  custom_commands = {},
+  notifications = {
+    backend = 'builtin', -- 'builtin' (vim.notify), 'nvim-notify' or 'fidget'
+    title = 'agent.nvim',
+    timeout = 3000, -- Milliseconds, used by nvim-notify
+    rate_limit_ms = 200, -- Notifications within this window are coalesced
+  },
 }
 
 local config = default_config
 
+-- Show a notification through the configured backend, falling back to vim.notify
+local function dispatch_notification(msg, level)
+  local opts = config.notifications or {}
+  
+  if opts.backend == 'nvim-notify' then
+    local ok, nvim_notify = pcall(require, 'notify')
+    if ok then
+      nvim_notify(msg, level, { title = opts.title, timeout = opts.timeout })
+      return
+    end
+  elseif opts.backend == 'fidget' then
+    local ok, fidget = pcall(require, 'fidget')
+    if ok and fidget.notify then
+      fidget.notify(msg, level, { annote = opts.title })
+      return
+    end
+  end
+  
+  vim.notify(msg, level)
+end
+
+-- Pending notifications, flushed at most once per rate limit window
+local notification_queue = {}
+local notification_flush_pending = false
+local flush_notifications
+
+-- Schedule a flush after the rate limit window unless one is already pending
+local function schedule_notification_flush()
+  if notification_flush_pending then
+    return
+  end
+  notification_flush_pending = true
+  vim.defer_fn(flush_notifications, (config.notifications or {}).rate_limit_ms or 200)
+end
+
+-- Flush the queued messages sharing the oldest message's level as one notification.
+-- Messages at other levels stay queued for the next window.
+flush_notifications = function()
+  notification_flush_pending = false
+  if #notification_queue == 0 then
+    return
+  end
+  
+  local level = notification_queue[1].level
+  local batch, remaining = {}, {}
+  for _, item in ipairs(notification_queue) do
+    table.insert(item.level == level and batch or remaining, item)
+  end
+  notification_queue = remaining
+  
+  -- Collapse repeated messages that share a dedup key
+  local entries, by_key = {}, {}
+  for _, item in ipairs(batch) do
+    local existing = item.key and by_key[item.key]
+    if existing then
+      existing.count = existing.count + 1
+      existing.msg = item.msg
+    else
+      local entry = { msg = item.msg, summary = item.summary, count = 1 }
+      if item.key then
+        by_key[item.key] = entry
+      end
+      table.insert(entries, entry)
+    end
+  end
+  
+  local lines = {}
+  for _, entry in ipairs(entries) do
+    local text = entry.msg
+    if entry.count > 1 then
+      local ok, summary = pcall(string.format, entry.summary or '', entry.count)
+      if entry.summary and ok then
+        text = summary
+      else
+        text = entry.msg .. ' (+' .. (entry.count - 1) .. ' more)'
+      end
+    end
+    table.insert(lines, text)
+  end
+  
+  dispatch_notification(table.concat(lines, '\n'), level or vim.log.levels.INFO)
+  
+  if #notification_queue > 0 then
+    schedule_notification_flush()
+  end
+end
+
+-- Queue a notification. `opts.key` collapses repeats, shown via `opts.summary`
+-- (a format string taking the count, e.g. 'Saved %d files') when given.
+local function notify(msg, level, opts)
+  opts = opts or {}
+  table.insert(notification_queue, { msg = msg, level = level, key = opts.key, summary = opts.summary })
+  schedule_notification_flush()
+end
+
 -- Get the plugin directory
 local function get_plugin_dir()
   -- Get the directory of this script file
@@ -55,7 +174,7 @@ local function find_rust_binary()
     local selector = dofile(selector_path)
     local binary_path = selector.get_binary_path(plugin_dir)
     if binary_path then
-      vim.notify('Found platform-specific binary: ' .. binary_path, vim.log.levels.DEBUG)
+      notify('Found platform-specific binary: ' .. binary_path, vim.log.levels.DEBUG)
       return binary_path
     end
   end
@@ -73,7 +192,7 @@ local function find_rust_binary()
   
   for _, path in ipairs(fallback_paths) do
     if vim.fn.executable(path) == 1 then
-      vim.notify('Found fallback binary: ' .. path, vim.log.levels.DEBUG)
+      notify('Found fallback binary: ' .. path, vim.log.levels.DEBUG)
       return path
     end
   end
@@ -90,15 +209,15 @@ function M.setup(user_config)
   
   if not config.rust_binary_path then
     local plugin_dir = get_plugin_dir()
-    vim.notify('agent.nvim: Rust binary not found initially', vim.log.levels.WARN)
-    vim.notify('Plugin directory: ' .. plugin_dir, vim.log.levels.INFO)
-    vim.notify('Binary will be searched again when needed', vim.log.levels.INFO)
+    notify('agent.nvim: Rust binary not found initially', vim.log.levels.WARN)
+    notify('Plugin directory: ' .. plugin_dir, vim.log.levels.INFO)
+    notify('Binary will be searched again when needed', vim.log.levels.INFO)
     
     -- Check if Rust is available
     if vim.fn.executable('cargo') == 1 then
-      vim.notify('Cargo found. You can build with: cd ' .. plugin_dir .. ' && cargo build', vim.log.levels.INFO)
+      notify('Cargo found. You can build with: cd ' .. plugin_dir .. ' && cargo build', vim.log.levels.INFO)
     else
-      vim.notify('Cargo not found. Install Rust from: https://rustup.rs/', vim.log.levels.WARN)
+      notify('Cargo not found. Install Rust from: https://rustup.rs/', vim.log.levels.WARN)
     end
   end
   
@@ -129,6 +248,32 @@ function M.setup(user_config)
     end
   end)
   
+  -- Register user-defined prompt commands
+  for name, template in pairs(config.custom_commands or {}) do
+    M.register_custom_command(name, template)
+  end
+  
+  -- Toggle tasks with <CR> and show progress in spec tasks documents
+  local tasks_group = vim.api.nvim_create_augroup('AgentSpecTasks', { clear = true })
+  vim.api.nvim_create_autocmd({ 'BufEnter', 'BufWritePost' }, {
+    group = tasks_group,
+    pattern = '*/.kiro/specs/*/tasks.md',
+    callback = function(args)
+      M.attach_tasks_buffer(args.buf)
+    end,
+  })
+  
+  -- Drop the progress bar when a window switches to another buffer
+  vim.api.nvim_create_autocmd('BufWinEnter', {
+    group = tasks_group,
+    callback = function(args)
+      if vim.w.agent_tasks_winbar and not vim.b[args.buf].agent_tasks_attached then
+        vim.wo.winbar = ''
+        vim.w.agent_tasks_winbar = nil
+      end
+    end,
+  })
+  
   -- Auto-start if configured and binary is available
   if config.auto_start and config.rust_binary_path then
     M.start_rust_backend()
@@ -142,7 +287,7 @@ function M.start_rust_backend()
   end
   
   if not config.rust_binary_path then
-    vim.notify('agent.nvim: Rust binary not found', vim.log.levels.ERROR)
+    notify('agent.nvim: Rust binary not found', vim.log.levels.ERROR)
     return false
   end
   
@@ -162,12 +307,12 @@ function M.start_rust_backend()
   })
   
   if state.rust_job_id <= 0 then
-    vim.notify('Failed to start agent.nvim backend', vim.log.levels.ERROR)
+    notify('Failed to start agent.nvim backend', vim.log.levels.ERROR)
     return false
   end
   
   state.initialized = true
-  vim.notify('agent.nvim backend started', vim.log.levels.INFO)
+  notify('agent.nvim backend started', vim.log.levels.INFO)
   
   -- Send a ping to test the connection
   vim.defer_fn(function()
@@ -203,7 +348,7 @@ end
 function M.handle_rust_error(data)
   for _, line in ipairs(data) do
     if line and line ~= '' then
-      vim.notify('Rust backend error: ' .. line, vim.log.levels.ERROR)
+      notify('Rust backend error: ' .. line, vim.log.levels.ERROR)
     end
   end
 end
@@ -214,7 +359,7 @@ function M.handle_rust_exit(code)
   state.initialized = false
   
   if code ~= 0 then
-    vim.notify('Rust backend exited with code: ' .. code, vim.log.levels.WARN)
+    notify('Rust backend exited with code: ' .. code, vim.log.levels.WARN)
   end
 end
 
@@ -222,19 +367,46 @@ end
 function M.handle_rust_message(message)
   local msg_type = message.type
   
-  if msg_type == 'window_create' then
+  if msg_type == 'startup' then
+    M.handle_startup(message.data)
+  elseif msg_type == 'window_create' then
     M.handle_window_create(message.data)
   elseif msg_type == 'window_update' then
     M.handle_window_update(message.data)
   elseif msg_type == 'chat_response' then
     M.handle_chat_response(message.data)
   elseif msg_type == 'notification' then
-    vim.notify(message.data.text, message.data.level)
+    notify(message.data.text, message.data.level, {
+      key = message.data.dedup_key,
+      summary = message.data.summary,
+    })
   elseif msg_type == 'spec_update' then
     M.handle_spec_update(message.data)
   end
 end
 
+-- Parse the major component of a semver-like version string
+local function major_version(version)
+  return tonumber(tostring(version or ''):match('^(%d+)'))
+end
+
+-- Handle startup message from Rust, rejecting an incompatible protocol
+function M.handle_startup(data)
+  local backend_version = data and data.protocol_version
+  if not backend_version then
+    return
+  end
+  
+  if major_version(backend_version) ~= major_version(PROTOCOL_VERSION) then
+    notify(
+      'agent.nvim: backend protocol ' .. backend_version .. ' is incompatible with frontend protocol '
+        .. PROTOCOL_VERSION .. '. Update the plugin so the Lua files and binary match.',
+      vim.log.levels.ERROR
+    )
+    M.stop_rust_backend()
+  end
+end
+
 -- Toggle agent interface (open/close)
 function M.toggle_agent()
   -- Check if interface is already open (check both input and chat windows)
@@ -262,17 +434,17 @@ function M.open_agent()
   if not config.rust_binary_path then
     config.rust_binary_path = find_rust_binary()
     if not config.rust_binary_path then
-      vim.notify('agent.nvim: Rust binary still not found', vim.log.levels.ERROR)
-      vim.notify('Try building with: cargo build', vim.log.levels.INFO)
+      notify('agent.nvim: Rust binary still not found', vim.log.levels.ERROR)
+      notify('Try building with: cargo build', vim.log.levels.INFO)
       return
     end
   end
   
   -- Ensure backend is running
   if not state.initialized then
-    vim.notify('Starting agent backend...', vim.log.levels.INFO)
+    notify('Starting agent backend...', vim.log.levels.INFO)
     if not M.start_rust_backend() then
-      vim.notify('Failed to start agent backend', vim.log.levels.ERROR)
+      notify('Failed to start agent backend', vim.log.levels.ERROR)
       return
     end
     
@@ -294,7 +466,7 @@ end
 -- Create new spec
 function M.new_spec(feature_name)
   if not state.initialized then
-    vim.notify('Agent backend not initialized', vim.log.levels.ERROR)
+    notify('Agent backend not initialized', vim.log.levels.ERROR)
     return
   end
   
@@ -312,7 +484,7 @@ end
 -- Open existing spec
 function M.open_spec(spec_name)
   if not state.initialized then
-    vim.notify('Agent backend not initialized', vim.log.levels.ERROR)
+    notify('Agent backend not initialized', vim.log.levels.ERROR)
     return
   end
   
@@ -321,7 +493,7 @@ function M.open_spec(spec_name)
     -- Show spec selection UI
     local specs = M.list_specs()
     if #specs == 0 then
-      vim.notify('No specs found', vim.log.levels.INFO)
+      notify('No specs found', vim.log.levels.INFO)
       return
     end
     
@@ -354,30 +526,107 @@ end
 -- Send message to Rust backend
 function M.send_to_rust(message)
   if not state.rust_job_id then
-    vim.notify('Rust backend not running', vim.log.levels.WARN)
+    notify('Rust backend not running', vim.log.levels.WARN)
     return false
   end
   
   local ok, json_message = pcall(vim.json.encode, message)
   if not ok then
-    vim.notify('Failed to encode message: ' .. tostring(json_message), vim.log.levels.ERROR)
+    notify('Failed to encode message: ' .. tostring(json_message), vim.log.levels.ERROR)
     return false
   end
   
   local success = pcall(vim.fn.chansend, state.rust_job_id, json_message .. '\n')
   if not success then
-    vim.notify('Failed to send message to backend', vim.log.levels.ERROR)
+    notify('Failed to send message to backend', vim.log.levels.ERROR)
     return false
   end
   
   return true
 end
 
+-- Check whether a window belongs to the agent interface
+local function is_agent_window(win)
+  for _, window in pairs(state.windows) do
+    if window.win == win then
+      return true
+    end
+  end
+  return false
+end
+
+-- Move focus out of the agent windows so files open in the main editor area
+local function focus_main_window()
+  local function is_main_window(win)
+    return win ~= 0 and not is_agent_window(win) and vim.api.nvim_win_get_config(win).relative == ''
+  end
+  
+  if is_main_window(vim.api.nvim_get_current_win()) then
+    return
+  end
+  
+  -- Prefer the previously focused window, then any other regular window
+  local previous = vim.fn.win_getid(vim.fn.winnr('#'))
+  if is_main_window(previous) then
+    vim.api.nvim_set_current_win(previous)
+    return
+  end
+  
+  for _, win in ipairs(vim.api.nvim_tabpage_list_wins(0)) do
+    if is_main_window(win) then
+      vim.api.nvim_set_current_win(win)
+      return
+    end
+  end
+end
+
+-- Open `buf` in an agent split, stacking chat above input once either exists
+local function open_split_window(buf, window_type)
+  local existing = window_type == 'chat' and state.windows.input or state.windows.chat
+  local anchor = existing and vim.api.nvim_win_is_valid(existing.win) and existing.win
+  local win
+  
+  vim.api.nvim_win_call(anchor or vim.api.nvim_get_current_win(), function()
+    if anchor then
+      vim.cmd(window_type == 'chat' and 'aboveleft split' or 'belowright split')
+    else
+      vim.cmd(config.ui.layout == 'right' and 'botright vsplit' or 'botright split')
+    end
+    win = vim.api.nvim_get_current_win()
+  end)
+  
+  vim.api.nvim_win_set_buf(win, buf)
+  vim.wo[win].number = false
+  vim.wo[win].relativenumber = false
+  vim.wo[win].signcolumn = 'no'
+  
+  -- Size the first split; later ones share its column or row
+  if not anchor then
+    if config.ui.layout == 'right' then
+      vim.api.nvim_win_set_width(win, math.floor(vim.o.columns * (config.ui.split_width_ratio or 0.35)))
+    else
+      vim.api.nvim_win_set_height(win, math.floor(vim.o.lines * (config.ui.window_height_ratio or 0.6)))
+    end
+  end
+  
+  return win
+end
+
+-- Whether an agent split's height can be set. A lone right split spans the full
+-- editor height, so resizing it would shrink the whole top-level row instead.
+local function can_set_split_height(window_type)
+  if config.ui.layout ~= 'right' then
+    return true
+  end
+  local other = window_type == 'chat' and state.windows.input or state.windows.chat
+  return other ~= nil and vim.api.nvim_win_is_valid(other.win)
+end
+
 -- Create dual window interface (chat history + input)
 function M.create_dual_window_interface()
   -- Ensure we have valid dimensions
   if vim.o.columns < 20 or vim.o.lines < 10 then
-    vim.notify('Terminal too small for agent interface', vim.log.levels.WARN)
+    notify('Terminal too small for agent interface', vim.log.levels.WARN)
     return
   end
   
@@ -391,10 +640,23 @@ function M.create_dual_window_interface()
   width = math.max(width, 40)
   chat_height = math.max(chat_height, 5)
   
-  -- Calculate positions (centered)
+  -- Keep custom ratios within the terminal (borders take a cell on each side)
+  width = math.min(width, vim.o.columns - 2)
+  total_height = math.min(total_height, vim.o.lines - 4)
+  chat_height = math.min(chat_height, math.max(total_height - input_height - 1, 1))
+  
+  -- Calculate positions (centered horizontally, vertically per anchor)
   local col = math.floor((vim.o.columns - width) / 2)
-  local chat_row = math.floor((vim.o.lines - total_height) / 2)
+  local chat_row
+  if config.ui.anchor == 'top' then
+    chat_row = 1
+  elseif config.ui.anchor == 'bottom' then
+    chat_row = math.max(vim.o.lines - total_height - 4, 0)
+  else
+    chat_row = math.floor((vim.o.lines - total_height) / 2)
+  end
   local input_row = chat_row + chat_height + 1 -- +1 for spacing
+  local use_splits = config.ui.layout == 'right' or config.ui.layout == 'bottom'
   
   -- Create chat history window (only if there's content)
   if state.chat_history and #state.chat_history > 0 then
@@ -424,12 +686,23 @@ function M.create_dual_window_interface()
         zindex = 40,
       }
       
-      local ok, chat_win = pcall(vim.api.nvim_open_win, chat_buf, false, chat_config)
+      local ok, chat_win
+      if use_splits then
+        chat_config = { split = true, height = chat_height }
+        ok, chat_win = pcall(open_split_window, chat_buf, 'chat')
+      else
+        ok, chat_win = pcall(vim.api.nvim_open_win, chat_buf, false, chat_config)
+      end
       if not ok then
-        vim.notify('Failed to create chat window: ' .. tostring(chat_win), vim.log.levels.ERROR)
+        notify('Failed to create chat window: ' .. tostring(chat_win), vim.log.levels.ERROR)
         return
       end
       
+      -- A chat split stacked onto the input only gets the input's few lines, so size it
+      if use_splits and can_set_split_height('chat') then
+        pcall(vim.api.nvim_win_set_height, chat_win, chat_height)
+      end
+      
       -- Enable syntax highlighting in the window
       pcall(vim.api.nvim_win_call, chat_win, function()
         vim.cmd('syntax enable')
@@ -442,12 +715,29 @@ function M.create_dual_window_interface()
         buf = chat_buf,
         win = chat_win,
         config = chat_config,
+        base_height = not use_splits and chat_height or nil,
       }
       
+      -- Any interaction with the chat window counts as activity
+      vim.api.nvim_create_autocmd({ 'CursorMoved', 'WinScrolled' }, {
+        buffer = chat_buf,
+        callback = function()
+          M.reset_inactivity_timer()
+        end,
+      })
+      
       -- Set up keymaps for chat window
       vim.keymap.set('n', 'q', function()
         M.close_agent_interface()
       end, { buffer = chat_buf, noremap = true, silent = true })
+      
+      vim.keymap.set('n', '<Tab>', function()
+        M.cycle_focus(1)
+      end, { buffer = chat_buf, noremap = true, silent = true })
+      
+      vim.keymap.set('n', '<S-Tab>', function()
+        M.cycle_focus(-1)
+      end, { buffer = chat_buf, noremap = true, silent = true })
     end
   end
   
@@ -469,9 +759,15 @@ function M.create_dual_window_interface()
       zindex = 50, -- Higher z-index for input window
     }
     
-    local ok, input_win = pcall(vim.api.nvim_open_win, input_buf, true, input_config)
+    local ok, input_win
+    if use_splits then
+      input_config = { split = true, height = input_height }
+      ok, input_win = pcall(open_split_window, input_buf, 'input')
+    else
+      ok, input_win = pcall(vim.api.nvim_open_win, input_buf, true, input_config)
+    end
     if not ok then
-      vim.notify('Failed to create input window: ' .. tostring(input_win), vim.log.levels.ERROR)
+      notify('Failed to create input window: ' .. tostring(input_win), vim.log.levels.ERROR)
       return
     end
     
@@ -479,9 +775,27 @@ function M.create_dual_window_interface()
       buf = input_buf,
       win = input_win,
       config = input_config,
+      base_height = input_height,
     }
     
+    -- Grow the input window as content is typed or pasted
+    vim.api.nvim_create_autocmd({ 'TextChanged', 'TextChangedI' }, {
+      buffer = input_buf,
+      callback = function()
+        M.grow_input_window()
+      end,
+    })
+    
+    -- Any interaction with the input window counts as activity
+    vim.api.nvim_create_autocmd({ 'CursorMoved', 'CursorMovedI', 'TextChanged', 'TextChangedI' }, {
+      buffer = input_buf,
+      callback = function()
+        M.reset_inactivity_timer()
+      end,
+    })
+    
     -- Set up keymaps for input window
+    -- Insert-mode <CR> inserts a newline; <C-CR> submits
     vim.keymap.set('n', '<CR>', function()
       M.send_message()
     end, { buffer = input_buf, noremap = true, silent = true })
@@ -498,6 +812,23 @@ function M.create_dual_window_interface()
       M.close_agent_interface()
     end, { buffer = input_buf, noremap = true, silent = true })
     
+    vim.keymap.set('n', '<Tab>', function()
+      M.cycle_focus(1)
+    end, { buffer = input_buf, noremap = true, silent = true })
+    
+    vim.keymap.set('n', '<S-Tab>', function()
+      M.cycle_focus(-1)
+    end, { buffer = input_buf, noremap = true, silent = true })
+    
+    -- Scroll the chat history without leaving the input window
+    vim.keymap.set({ 'n', 'i' }, '<C-f>', function()
+      M.scroll_chat(1)
+    end, { buffer = input_buf, noremap = true, silent = true })
+    
+    vim.keymap.set({ 'n', 'i' }, '<C-b>', function()
+      M.scroll_chat(-1)
+    end, { buffer = input_buf, noremap = true, silent = true })
+    
     -- Start in insert mode for immediate typing
     vim.schedule(function()
       if vim.api.nvim_win_is_valid(input_win) then
@@ -506,6 +837,131 @@ function M.create_dual_window_interface()
       end
     end)
   end
+  
+  -- Stacked splits share space, so restore the input height afterwards
+  if use_splits and state.windows.input and vim.api.nvim_win_is_valid(state.windows.input.win)
+    and can_set_split_height('input') then
+    pcall(vim.api.nvim_win_set_height, state.windows.input.win, state.windows.input.config.height)
+  end
+  
+  M.reset_inactivity_timer()
+end
+
+-- Restart the inactivity timer; no-op unless ui.inactivity_timeout is set
+function M.reset_inactivity_timer()
+  local timeout = config.ui.inactivity_timeout
+  if not timeout or timeout <= 0 then
+    return
+  end
+  
+  if not state.inactivity_timer then
+    state.inactivity_timer = vim.loop.new_timer()
+  end
+  
+  state.inactivity_timer:stop()
+  state.inactivity_timer:start(timeout * 1000, 0, vim.schedule_wrap(function()
+    M.handle_inactivity()
+  end))
+end
+
+-- Save state and close the interface once the inactivity timeout expires
+function M.handle_inactivity()
+  local is_open = false
+  for _, window in pairs(state.windows) do
+    if vim.api.nvim_win_is_valid(window.win) then
+      is_open = true
+    end
+  end
+  
+  -- Windows may already have been closed by other means (e.g. :q)
+  if not is_open then
+    return
+  end
+  
+  if state.initialized then
+    M.save_state()
+  end
+  M.close_agent_interface()
+  notify('Agent interface closed after inactivity', vim.log.levels.INFO)
+end
+
+-- Move focus to the next (1) or previous (-1) open agent window
+function M.cycle_focus(direction)
+  local current = vim.api.nvim_get_current_win()
+  local windows = {}
+  local current_index = 0
+  
+  for _, window_type in ipairs(FOCUS_ORDER) do
+    local window = state.windows[window_type]
+    if window and vim.api.nvim_win_is_valid(window.win) then
+      table.insert(windows, window.win)
+      if window.win == current then
+        current_index = #windows
+      end
+    end
+  end
+  
+  if #windows == 0 then
+    return
+  end
+  
+  local next_index = (current_index + direction - 1) % #windows + 1
+  vim.api.nvim_set_current_win(windows[next_index])
+end
+
+-- Scroll the chat window a page down (1) or up (-1) without focusing it
+function M.scroll_chat(direction)
+  local chat = state.windows.chat
+  if not chat or not vim.api.nvim_win_is_valid(chat.win) then
+    return
+  end
+  
+  local keys = vim.api.nvim_replace_termcodes(direction > 0 and '<C-f>' or '<C-b>', true, false, true)
+  vim.api.nvim_win_call(chat.win, function()
+    vim.cmd('normal! ' .. keys)
+  end)
+end
+
+-- Calculate the input window height needed to show `line_count` lines
+local function calculate_input_height(line_count)
+  local min_height = 3
+  -- Leave room for the borders and the command line
+  local max_height = math.min(config.ui.max_input_height or 10, vim.o.lines - 4)
+  return math.max(min_height, math.min(line_count, max_height))
+end
+
+-- Resize the input window to fit its content, keeping its bottom edge fixed
+function M.grow_input_window()
+  local input = state.windows.input
+  if not input or not vim.api.nvim_win_is_valid(input.win) then
+    return
+  end
+  
+  local line_count = vim.api.nvim_buf_line_count(input.buf)
+  local height = calculate_input_height(line_count)
+  if height == input.config.height then
+    return
+  end
+  
+  if input.config.split then
+    input.config.height = height
+    if can_set_split_height('input') then
+      pcall(vim.api.nvim_win_set_height, input.win, height)
+    end
+    return
+  end
+  
+  local bottom = input.config.row + input.config.height
+  input.config.height = height
+  input.config.row = math.max(bottom - height, 0)
+  pcall(vim.api.nvim_win_set_config, input.win, input.config)
+  
+  -- Shrink the chat float by the same amount so the input doesn't cover its newest lines
+  local chat = state.windows.chat
+  if chat and chat.base_height and vim.api.nvim_win_is_valid(chat.win) then
+    chat.config.height = math.max(chat.base_height - (height - input.base_height), 1)
+    pcall(vim.api.nvim_win_set_config, chat.win, chat.config)
+  end
 end
 
 -- Close agent interface
@@ -533,8 +989,12 @@ function M.close_agent_interface()
   -- Clear the entire windows table to ensure clean state
   state.windows = {}
   
+  if state.inactivity_timer then
+    state.inactivity_timer:stop()
+  end
+  
   if closed_windows > 0 then
-    vim.notify('Agent interface closed', vim.log.levels.INFO)
+    notify('Agent interface closed', vim.log.levels.INFO)
   end
   
   -- Also notify Rust backend
@@ -559,13 +1019,22 @@ function M.send_message()
   
   -- Clear input buffer
   vim.api.nvim_buf_set_lines(state.windows.input.buf, 0, -1, false, { '' })
+  M.grow_input_window()
   
+  M.submit_message(message)
+end
+
+-- Add a user message to the chat history and send it to the backend
+function M.submit_message(message)
   -- Add message to chat history
   if not state.chat_history then
     state.chat_history = {}
   end
   
-  table.insert(state.chat_history, '**You:** ' .. message)
+  -- Buffer lines can't contain newlines, so add multiline messages line by line
+  local message_lines = vim.split(message, '\n', { plain = true })
+  message_lines[1] = '**You:** ' .. message_lines[1]
+  vim.list_extend(state.chat_history, message_lines)
   table.insert(state.chat_history, '')
   
   -- Send to Rust backend
@@ -620,8 +1089,11 @@ function M.handle_chat_response(data)
     state.chat_history = {}
   end
   
+  -- Agent output counts as activity, so a slow reply doesn't close the interface
+  M.reset_inactivity_timer()
+  
   -- Add agent response to chat history
-  table.insert(state.chat_history, data.message)
+  vim.list_extend(state.chat_history, vim.split(data.message or '', '\n', { plain = true }))
   table.insert(state.chat_history, '')
   
   -- Update chat window if it exists
@@ -636,12 +1108,17 @@ end
 -- Handle spec updates from Rust
 function M.handle_spec_update(data)
   state.current_spec = data.spec_name
-  vim.notify('Spec updated: ' .. data.action, vim.log.levels.INFO)
+  notify('Spec updated: ' .. data.action, vim.log.levels.INFO)
+end
+
+-- Get the directory specs are stored in
+local function get_spec_root()
+  return vim.fn.getcwd() .. '/.kiro/specs'
 end
 
 -- List available specs
 function M.list_specs()
-  local spec_dir = vim.fn.getcwd() .. '/.kiro/specs'
+  local spec_dir = get_spec_root()
   if vim.fn.isdirectory(spec_dir) == 0 then
     return {}
   end
@@ -661,6 +1138,432 @@ function M.list_specs()
   return specs
 end
 
+-- Resolve the spec directory and phase index of the current buffer
+local function current_spec_document()
+  local path = vim.api.nvim_buf_get_name(0)
+  local file = vim.fn.fnamemodify(path, ':t')
+  
+  -- Only phase documents directly inside a spec under the spec root count
+  if path ~= '' and vim.fn.fnamemodify(path, ':p:h:h') == get_spec_root() then
+    for index, name in ipairs(PHASE_DOCUMENTS) do
+      if file == name then
+        return vim.fn.fnamemodify(path, ':p:h'), index
+      end
+    end
+  end
+  
+  -- Not in a phase document; fall back to the active spec
+  if state.current_spec then
+    return get_spec_root() .. '/' .. state.current_spec, nil
+  end
+  
+  return nil, nil
+end
+
+-- Open the phase document `direction` steps away from the current one
+function M.goto_adjacent_phase(direction)
+  local spec_dir, index = current_spec_document()
+  if not spec_dir then
+    notify('No spec document open', vim.log.levels.WARN)
+    return
+  end
+  
+  local target = (index or 0) + direction
+  if target < 1 or target > #PHASE_DOCUMENTS then
+    notify('No ' .. (direction > 0 and 'next' or 'previous') .. ' phase', vim.log.levels.INFO)
+    return
+  end
+  
+  -- Open in the main window, leaving the agent interface (and any draft) intact
+  focus_main_window()
+  vim.cmd('edit ' .. vim.fn.fnameescape(spec_dir .. '/' .. PHASE_DOCUMENTS[target]))
+end
+
+-- Open the next phase document
+function M.next_phase()
+  M.goto_adjacent_phase(1)
+end
+
+-- Open the previous phase document
+function M.prev_phase()
+  M.goto_adjacent_phase(-1)
+end
+
+-- Build a snippet of about `width` bytes centred on the match at `col`
+local function search_snippet(line, col, query_len, width)
+  width = width or 60
+  local start = math.max(1, col - math.floor((width - query_len) / 2))
+  local finish = math.min(#line, start + width - 1)
+  start = math.max(1, finish - width + 1)
+  
+  -- Snap to UTF-8 character boundaries; continuation bytes are 0b10xxxxxx
+  local function is_continuation(index)
+    local byte = line:byte(index)
+    return byte ~= nil and byte >= 0x80 and byte <= 0xBF
+  end
+  while start > 1 and is_continuation(start) do
+    start = start - 1
+  end
+  while finish < #line and is_continuation(finish + 1) do
+    finish = finish + 1
+  end
+  
+  local snippet = vim.trim(line:sub(start, finish))
+  if start > 1 then
+    snippet = '...' .. snippet
+  end
+  if finish < #line then
+    snippet = snippet .. '...'
+  end
+  return snippet
+end
+
+-- Search the phase documents of the given specs for a case-insensitive query
+local function collect_spec_hits(spec_names, query)
+  local hits = {}
+  local needle = query:lower()
+  
+  for _, spec_name in ipairs(spec_names) do
+    for _, doc in ipairs(PHASE_DOCUMENTS) do
+      local path = get_spec_root() .. '/' .. spec_name .. '/' .. doc
+      if vim.fn.filereadable(path) == 1 then
+        for lnum, line in ipairs(vim.fn.readfile(path)) do
+          local col = line:lower():find(needle, 1, true)
+          if col then
+            table.insert(hits, {
+              spec = spec_name,
+              phase = (doc:gsub('%.md$', '')),
+              filename = path,
+              lnum = lnum,
+              col = col,
+              text = vim.trim(line),
+              snippet = search_snippet(line, col, #needle),
+            })
+          end
+        end
+      end
+    end
+  end
+  
+  return hits
+end
+
+-- Find a term across the current spec's documents (or every spec) in the quickfix list
+function M.find_in_spec(query, all_specs)
+  if not query or query == '' then
+    return
+  end
+  
+  local spec_names
+  if all_specs then
+    spec_names = M.list_specs()
+  else
+    local spec_dir = current_spec_document()
+    if not spec_dir or vim.fn.isdirectory(spec_dir) == 0 then
+      notify('No active spec; use ! to search all specs', vim.log.levels.WARN)
+      return
+    end
+    spec_names = { vim.fn.fnamemodify(spec_dir, ':t') }
+  end
+  
+  local hits = collect_spec_hits(spec_names, query)
+  if #hits == 0 then
+    notify('No matches for: ' .. query, vim.log.levels.INFO)
+    return
+  end
+  
+  local items = {}
+  for _, hit in ipairs(hits) do
+    table.insert(items, {
+      filename = hit.filename,
+      lnum = hit.lnum,
+      col = hit.col,
+      text = '[' .. hit.phase .. '] ' .. hit.text,
+    })
+  end
+  
+  vim.fn.setqflist({}, ' ', { title = 'Spec search: ' .. query, items = items })
+  vim.cmd('copen')
+end
+
+-- Count completed and total checkbox tasks in a buffer
+local function task_stats(buf)
+  local completed, total = 0, 0
+  for _, line in ipairs(vim.api.nvim_buf_get_lines(buf, 0, -1, false)) do
+    local mark = line:match('^%s*[-*] %[([ xX])%]')
+    if mark then
+      total = total + 1
+      if mark ~= ' ' then
+        completed = completed + 1
+      end
+    end
+  end
+  return completed, total
+end
+
+-- Show task completion as a progress bar in the winbar of windows showing `buf`
+local function update_task_progress(buf)
+  if vim.fn.has('nvim-0.8') == 0 then
+    return
+  end
+  
+  local completed, total = task_stats(buf)
+  local percent = total > 0 and math.floor(completed * 100 / total) or 0
+  local filled = math.floor(percent / 10)
+  local bar = string.rep('█', filled) .. string.rep('░', 10 - filled)
+  -- '%%%%' escapes to a literal '%' in the winbar
+  local title = string.format('Tasks %s %d%%%% (%d/%d)', bar, percent, completed, total)
+  
+  for _, win in ipairs(vim.fn.win_findbuf(buf)) do
+    vim.wo[win].winbar = title
+    vim.w[win].agent_tasks_winbar = true
+  end
+end
+
+-- Set up task toggling for a tasks.md buffer
+function M.attach_tasks_buffer(buf)
+  if not vim.b[buf].agent_tasks_attached then
+    vim.keymap.set('n', '<CR>', function()
+      if M.toggle_task() == nil then
+        pcall(vim.cmd, 'normal! +')
+      end
+    end, { buffer = buf, noremap = true, silent = true, desc = 'Toggle task' })
+    vim.b[buf].agent_tasks_attached = true
+  end
+  
+  update_task_progress(buf)
+end
+
+-- Toggle the checkbox on the cursor line, returning the new state (nil if not a task)
+function M.toggle_task()
+  local buf = vim.api.nvim_get_current_buf()
+  local lnum = vim.api.nvim_win_get_cursor(0)[1]
+  local line = vim.api.nvim_buf_get_lines(buf, lnum - 1, lnum, false)[1]
+  
+  local prefix, mark, rest = line:match('^(%s*[-*] %[)([ xX])(%].*)$')
+  if not prefix then
+    return nil
+  end
+  
+  local completed = mark == ' '
+  vim.api.nvim_buf_set_lines(buf, lnum - 1, lnum, false, { prefix .. (completed and 'x' or ' ') .. rest })
+  -- Saving triggers BufWritePost, which refreshes the progress bar
+  pcall(vim.cmd, 'silent update')
+  
+  return completed
+end
+
+-- Search every spec's documents and jump to the selected match
+function M.search_specs(query)
+  if not query or query == '' then
+    return
+  end
+  
+  local hits = collect_spec_hits(M.list_specs(), query)
+  if #hits == 0 then
+    notify('No specs match: ' .. query, vim.log.levels.INFO)
+    return
+  end
+  
+  vim.ui.select(hits, {
+    prompt = 'Spec matches for "' .. query .. '":',
+    format_item = function(hit)
+      return hit.spec .. ' › ' .. hit.phase .. ':' .. hit.lnum .. '  ' .. hit.snippet
+    end,
+  }, function(choice)
+    if not choice then
+      return
+    end
+    
+    -- Open in the main window, leaving the agent interface intact
+    focus_main_window()
+    
+    vim.cmd('edit ' .. vim.fn.fnameescape(choice.filename))
+    vim.api.nvim_win_set_cursor(0, { choice.lnum, choice.col - 1 })
+  end)
+end
+
+-- Start the backend (and interface) if needed, returning whether it is running
+local function ensure_backend_started()
+  if not state.initialized then
+    M.open_agent()
+  end
+  return state.initialized
+end
+
+-- Expand a custom command template for the current buffer and command range
+local function expand_template(template, opts)
+  local buf = vim.api.nvim_get_current_buf()
+  local selection = {}
+  if opts.range > 0 then
+    selection = vim.api.nvim_buf_get_lines(buf, opts.line1 - 1, opts.line2, false)
+  end
+  
+  local values = {
+    file = vim.fn.expand('%:p'),
+    filetype = vim.bo[buf].filetype,
+    buffer = table.concat(vim.api.nvim_buf_get_lines(buf, 0, -1, false), '\n'),
+    selection = table.concat(selection, '\n'),
+    args = opts.args or '',
+  }
+  
+  return (template:gsub('{(%w+)}', function(key)
+    return values[key]
+  end))
+end
+
+-- Command names created by register_custom_command, which may be re-registered
+local registered_custom_commands = {}
+
+-- Register a :Spec<Name> command that sends a prompt template as a user message.
+-- Templates may use {file}, {filetype}, {buffer}, {selection} and {args}.
+function M.register_custom_command(name, template)
+  -- User command names may only contain letters and digits
+  if type(name) ~= 'string' or not name:match('^%a%w*$') then
+    notify('agent.nvim: invalid custom command name "' .. tostring(name)
+      .. '"; use letters and digits only (e.g. "Explain")', vim.log.levels.WARN)
+    return false
+  end
+  
+  local command_name = 'Spec' .. name:sub(1, 1):upper() .. name:sub(2)
+  -- Never replace the plugin's own commands (or anyone else's)
+  if vim.fn.exists(':' .. command_name) == 2 and not registered_custom_commands[command_name] then
+    notify('agent.nvim: custom command "' .. name .. '" conflicts with existing :'
+      .. command_name .. '; choose another name', vim.log.levels.WARN)
+    return false
+  end
+  
+  config.custom_commands = config.custom_commands or {}
+  config.custom_commands[name] = template
+  
+  vim.api.nvim_create_user_command(command_name, function(opts)
+    if not ensure_backend_started() then
+      return
+    end
+    
+    M.submit_message(expand_template(template, opts))
+  end, { nargs = '*', range = true, force = true, desc = 'Agent: ' .. name })
+  registered_custom_commands[command_name] = true
+  
+  return true
+end
+
+-- Byte index of the last byte of the UTF-8 character starting at or containing `col`
+local function utf8_char_end(line, col)
+  while col < #line do
+    local next_byte = line:byte(col + 1)
+    -- Continuation bytes are 0b10xxxxxx
+    if next_byte < 0x80 or next_byte > 0xBF then
+      break
+    end
+    col = col + 1
+  end
+  return col
+end
+
+-- Slice `line` to the characters overlapping display columns `left`..`right`
+local function slice_display_columns(line, left, right)
+  local parts = {}
+  local vcol = 0
+  for _, char in ipairs(vim.fn.split(line, '\\zs')) do
+    local width = vim.fn.strdisplaywidth(char, vcol)
+    if vcol + width >= left and vcol + 1 <= right then
+      table.insert(parts, char)
+    end
+    vcol = vcol + width
+  end
+  return table.concat(parts)
+end
+
+-- First and last display column of the character at a getpos() position
+local function position_display_columns(pos)
+  local first = pos[3] > 1 and vim.fn.virtcol({ pos[2], pos[3] - 1 }) + 1 or 1
+  return first, vim.fn.virtcol({ pos[2], pos[3] })
+end
+
+-- Get the lines of the command range, honouring charwise and blockwise visual selections
+local function get_selection_lines(buf, opts)
+  local lines = vim.api.nvim_buf_get_lines(buf, opts.line1 - 1, opts.line2, false)
+  local start_pos = vim.fn.getpos("'<")
+  local end_pos = vim.fn.getpos("'>")
+  
+  -- Only trust the visual marks when they match the command range
+  if start_pos[2] ~= opts.line1 or end_pos[2] ~= opts.line2 or #lines == 0 then
+    return lines
+  end
+  
+  local mode = vim.fn.visualmode()
+  if vim.fn.exists('*getregion') == 1 then
+    return vim.fn.getregion(start_pos, end_pos, { type = mode })
+  end
+  
+  -- getpos() columns are byte indexes, so adjust for multibyte text and tabs
+  if mode == 'v' then
+    -- Charwise: trim the first and last lines, keeping the whole last character
+    lines[#lines] = lines[#lines]:sub(1, utf8_char_end(lines[#lines], end_pos[3]))
+    lines[1] = lines[1]:sub(start_pos[3])
+  elseif mode == '\22' then
+    -- Blockwise: take the same display columns from every line
+    local start_first, start_last = position_display_columns(start_pos)
+    local end_first, end_last = position_display_columns(end_pos)
+    local left = math.min(start_first, end_first)
+    local right = math.max(start_last, end_last)
+    if end_pos[3] > #lines[#lines] then
+      right = math.huge -- Block extended to end of line with $
+    end
+    for i, line in ipairs(lines) do
+      lines[i] = slice_display_columns(line, left, right)
+    end
+  end
+  
+  return lines
+end
+
+-- Ask the agent about the selected lines, sent as a fenced code block
+function M.ask_selection(opts)
+  if opts.range == 0 then
+    notify('Select some lines to ask about', vim.log.levels.WARN)
+    return
+  end
+  
+  local buf = vim.api.nvim_get_current_buf()
+  local question = opts.args
+  if not question or question == '' then
+    question = vim.fn.input('Ask about selection: ')
+    if question == '' then
+      return
+    end
+  end
+  
+  local file = vim.fn.expand('%:.')
+  local language = vim.bo[buf].filetype
+  local parts = {
+    question,
+    '',
+    string.format('Selection from `%s` (lines %d-%d):', file, opts.line1, opts.line2),
+    '```' .. language,
+  }
+  vim.list_extend(parts, get_selection_lines(buf, opts))
+  table.insert(parts, '```')
+  
+  local context_lines = config.ui.selection_context_lines or 0
+  if context_lines > 0 then
+    local first = math.max(1, opts.line1 - context_lines)
+    local last = math.min(vim.api.nvim_buf_line_count(buf), opts.line2 + context_lines)
+    table.insert(parts, '')
+    table.insert(parts, string.format('Surrounding context (lines %d-%d):', first, last))
+    table.insert(parts, '```' .. language)
+    vim.list_extend(parts, vim.api.nvim_buf_get_lines(buf, first - 1, last, false))
+    table.insert(parts, '```')
+  end
+  
+  if not ensure_backend_started() then
+    return
+  end
+  
+  M.submit_message(table.concat(parts, '\n'))
+end
+
 -- Auto-start function
 function M.auto_start()
   if config.auto_start then
@@ -683,12 +1586,13 @@ function M.show_status()
   local status = {
     'nvim-spec-agent Status:',
     '  Backend: ' .. (state.initialized and 'Running' or 'Stopped'),
+    '  Protocol: ' .. PROTOCOL_VERSION,
     '  Job ID: ' .. (state.rust_job_id or 'None'),
     '  Current Spec: ' .. (state.current_spec or 'None'),
     '  Windows: ' .. vim.tbl_count(state.windows),
   }
   
-  vim.notify(table.concat(status, '\n'), vim.log.levels.INFO)
+  notify(table.concat(status, '\n'), vim.log.levels.INFO)
 end
 
 -- Get plugin configuration
@@ -711,14 +1615,14 @@ function M.debug_paths()
     plugin_dir .. '/target/release/nvim-spec-agent.exe',
   }
   
-  vim.notify('Plugin directory: ' .. plugin_dir, vim.log.levels.INFO)
-  vim.notify('Searching for binary in:', vim.log.levels.INFO)
+  notify('Plugin directory: ' .. plugin_dir, vim.log.levels.INFO)
+  notify('Searching for binary in:', vim.log.levels.INFO)
   for _, path in ipairs(paths) do
     local exists = vim.fn.filereadable(path) == 1
-    vim.notify('  ' .. path .. ' - ' .. (exists and 'EXISTS' or 'NOT FOUND'), vim.log.levels.INFO)
+    notify('  ' .. path .. ' - ' .. (exists and 'EXISTS' or 'NOT FOUND'), vim.log.levels.INFO)
   end
   
-  vim.notify('Build script: ' .. plugin_dir .. '/build.sh - ' .. 
+  notify('Build script: ' .. plugin_dir .. '/build.sh - ' .. 
     (vim.fn.filereadable(plugin_dir .. '/build.sh') == 1 and 'EXISTS' or 'NOT FOUND'), vim.log.levels.INFO)
 end
 
@@ -21,6 +21,11 @@ EOF
 
 " Commands
 command! -nargs=0 AgentToggle lua require('agent').toggle_agent()
+command! -nargs=0 SpecAgentPhaseNext lua require('agent').next_phase()
+command! -nargs=0 SpecAgentPhasePrev lua require('agent').prev_phase()
+command! -nargs=+ -bang SpecAgentFind lua require('agent').find_in_spec(<q-args>, '<bang>' == '!')
+command! -nargs=+ SpecSearch lua require('agent').search_specs(<q-args>)
+command! -nargs=* -range SpecAskSelection lua require('agent').ask_selection({ range = <range>, line1 = <line1>, line2 = <line2>, args = <q-args> })
 
 " Auto-commands
 augroup AgentNvim